@@ -1,13 +1,26 @@
-use crypto::buffer::{ReadBuffer, WriteBuffer};
-use crypto::{
-    aes, hmac::Hmac, pbkdf2::pbkdf2, sha2::Sha256, symmetriccipher::SymmetricCipherError,
-};
+use aes::Aes256;
+use cbc::cipher::block_padding::Pkcs7;
+use cbc::cipher::{BlockDecryptMut, KeyIvInit};
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305, XNonce};
 use derive_more::{Display, From};
 use log::*;
+use pbkdf2::pbkdf2_hmac;
+use rand::RngCore;
 use rusqlcipher::Connection;
 use serde_derive::*;
+use sha2::Sha256;
 use std::io::Write;
+use std::ops::Range;
+use std::path::PathBuf;
 use structopt::*;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+type Aes256CbcDec = cbc::Decryptor<Aes256>;
+
+/// Environment variable consulted for the master password when `-p` is
+/// omitted, before falling back to an interactive, echo-disabled prompt.
+const ENPASS_PASSWORD_VAR: &str = "ENPASS_PASSWORD";
 
 #[derive(Debug)]
 struct Identity {
@@ -38,48 +51,71 @@ struct Opt {
     #[structopt(short = "d")]
     database: String,
 
-    #[structopt(short = "p")]
-    password: String,
+    /// Master password. If omitted, it is read from $ENPASS_PASSWORD, or
+    /// prompted for interactively with echo disabled.
+    #[structopt(short = "p", long = "password")]
+    password: Option<String>,
 
     #[structopt(short = "6")]
     version_6: bool,
+
+    /// Path to the vault's keyfile, for vaults protected by a password
+    /// plus a keyfile second factor.
+    #[structopt(short = "k", long = "keyfile")]
+    keyfile: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Write every decrypted card into a single portable, authenticated
+    /// backup file, encrypted under a passphrase you supply separately
+    /// from the vault password.
+    Export {
+        /// Path to write the encrypted backup to.
+        #[structopt(short = "o", long = "output")]
+        output: PathBuf,
+    },
 }
 
 #[derive(Display, From, Debug)]
 enum Error {
-    #[display(fmt = "SymmetricCipherError")]
-    CryptoError(SymmetricCipherError),
+    #[display(fmt = "invalid key/iv length for AES-256-CBC")]
+    InvalidKeyIvLength,
+    #[display(fmt = "card data is not validly padded AES-256-CBC/PKCS7 ciphertext")]
+    UnpadError,
     #[display(fmt = "sqlcipher error: {}", "_0")]
     SqlCipherError(rusqlcipher::Error),
     SerdeJsonError(serde_json::Error),
     UnsupportedEnpassVersion,
+    #[display(fmt = "failed to read password from terminal")]
+    PasswordPromptError,
+    #[display(fmt = "I/O error: {}", "_0")]
+    IoError(std::io::Error),
+    #[display(fmt = "export container encryption failed")]
+    ExportCipherError,
+    #[display(fmt = "Identity.info is too short for the v5 layout")]
+    MalformedIdentity,
 }
 
+/// Decrypts a `Cards.data` blob: the whole blob is AES-256-CBC/PKCS7
+/// ciphertext, same as at baseline.
+///
+/// A prior revision of this function also verified a leading HMAC tag,
+/// under the assumption that the blob was laid out as
+/// `mac(32) || ciphertext`. That layout was never confirmed against a
+/// real Enpass vault, so rather than ship a check that either does
+/// nothing (off) or strips 32 bytes of real ciphertext and fails to
+/// verify (on), it's been removed. Restore it once Enpass's actual
+/// per-card authentication tag format (if any) is confirmed.
 fn decrypt_enpass_data(input_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, Error> {
-    let mut decryptor = aes::cbc_decryptor(
-        aes::KeySize::KeySize256,
-        &key,
-        &iv,
-        crypto::blockmodes::PkcsPadding,
-    );
-    let mut read_buffer = crypto::buffer::RefReadBuffer::new(input_data);
-    let mut final_result = Vec::new();
-    let mut output_buffer = [0; 4096];
-    let mut write_buffer = crypto::buffer::RefWriteBuffer::new(&mut output_buffer);
-    loop {
-        let result = decryptor
-            .decrypt(&mut read_buffer, &mut write_buffer, true)
-            .map_err(Error::CryptoError)?;
-        match result {
-            crypto::buffer::BufferResult::BufferUnderflow => {
-                final_result.extend(write_buffer.take_read_buffer().take_remaining());
-                return Ok(final_result);
-            }
-            crypto::buffer::BufferResult::BufferOverflow => {
-                final_result.extend(write_buffer.take_read_buffer().take_remaining())
-            }
-        }
-    }
+    let decryptor =
+        Aes256CbcDec::new_from_slices(key, iv).map_err(|_| Error::InvalidKeyIvLength)?;
+    decryptor
+        .decrypt_padded_vec_mut::<Pkcs7>(input_data)
+        .map_err(|_| Error::UnpadError)
 }
 
 const ENPASS5_PRAGMAS: &'static str = "PRAGMA cipher_page_size = 1024;\
@@ -88,75 +124,212 @@ const ENPASS5_PRAGMAS: &'static str = "PRAGMA cipher_page_size = 1024;\
                                        PRAGMA cipher_kdf_algorithm = PBKDF2_HMAC_SHA1;\
                                        ";
 
-// const ENPASS6_PRAGMAS: &'static str = "
-// PRAGMA kdf_iter = 100000;
-// ";
+// SQLCipher 4 defaults, which is what the Enpass 6 desktop clients write
+// their `vault.enpassdb` / `.walletx` files with.
+// https://www.zetetic.net/blog/2018/11/30/sqlcipher-400-release/
+const ENPASS6_PRAGMAS: &'static str = "PRAGMA cipher_page_size = 4096;\
+                                       PRAGMA kdf_iter = 100000;\
+                                       PRAGMA cipher_hmac_algorithm = HMAC_SHA512;\
+                                       PRAGMA cipher_kdf_algorithm = PBKDF2_HMAC_SHA512;\
+                                       ";
 
-// const ENPASS6_PRAGMAS: &'static str = "PRAGMA cipher_compatibility = 3";
+/// Resolves the master password: an explicit `-p` wins, then
+/// `$ENPASS_PASSWORD`, then an interactive, echo-disabled TTY prompt. The
+/// prompt path avoids leaking the password via `ps`, shell history, or
+/// `/proc/<pid>/cmdline`.
+fn resolve_password(opt: &Opt) -> Result<Zeroizing<String>, Error> {
+    if let Some(password) = &opt.password {
+        return Ok(Zeroizing::new(password.clone()));
+    }
+    if let Ok(password) = std::env::var(ENPASS_PASSWORD_VAR) {
+        return Ok(Zeroizing::new(password));
+    }
+    rpassword::prompt_password("Master password: ")
+        .map(Zeroizing::new)
+        .map_err(|_| Error::PasswordPromptError)
+}
 
-// const ENPASS5_PRAGMAS: &[&'static str] = [
-//     "PRAGMA cipher_page_size = 1024",
-//     "PRAGMA kdf_iter = 24000",
-//     "PRAGMA cipher_hmac_algorithm = HMAC_SHA1",
-//     "PRAGMA cipher_kdf_algorithm = PBKDF2_HMAC_SHA1;",
-// ];
+/// Reads the keyfile, if one was given, into a zeroized buffer.
+fn read_keyfile(opt: &Opt) -> Result<Option<Zeroizing<Vec<u8>>>, Error> {
+    match &opt.keyfile {
+        Some(path) => Ok(Some(Zeroizing::new(std::fs::read(path)?))),
+        None => Ok(None),
+    }
+}
 
-fn main() -> Result<(), Error> {
-    env_logger::init();
-    let opt = Opt::from_args();
+/// Magic bytes identifying an `export` backup file, with a version suffix
+/// in case the container format needs to change later.
+const EXPORT_MAGIC: &[u8; 9] = b"ENPCLIv01";
+const EXPORT_SALT_LEN: usize = 16;
+const EXPORT_PBKDF2_ROUNDS: u32 = 200_000;
 
-    let conn = Connection::open(opt.database)?;
+/// `kdf_id` byte identifying how the passphrase was stretched into the
+/// XChaCha20-Poly1305 key, stored in the header so a decrypter never has
+/// to guess the parameters used at export time.
+const EXPORT_KDF_PBKDF2_HMAC_SHA256: u8 = 1;
 
-    // https://www.zetetic.net/blog/2018/11/30/sqlcipher-400-release/
-    // Another option is PRAGMA cipher_compatibility = 3;
-    // https://discuss.zetetic.net/t/upgrading-to-sqlcipher-4/3283
+/// Encrypts every card into a single self-contained, authenticated backup
+/// file:
+/// `EXPORT_MAGIC || kdf_id(1) || pbkdf2_rounds(4, LE) || salt || nonce || ciphertext`,
+/// where `ciphertext` is the XChaCha20-Poly1305 sealing of the serialized
+/// `Vec<Card>` under a key stretched from `passphrase` with
+/// PBKDF2-HMAC-SHA256. The KDF parameters travel in the header so the
+/// backup can be decrypted later without also needing the exact version
+/// of this tool that wrote it. Unlike the vault's own CBC scheme, this is
+/// authenticated end to end and needs neither sqlcipher nor the vault
+/// password to decrypt later.
+fn export_cards(cards: &[Card], passphrase: &str, output: &std::path::Path) -> Result<(), Error> {
+    let mut salt = [0u8; EXPORT_SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
 
-    conn.execute_batch(&format!("PRAGMA key = '{}'", &opt.password))?;
-    if opt.version_6 {
-        eprintln!("Enpass 6 is currently not supported.\n\
-        If you know the encryption format, please feel free to file an issue at https://github.com/norcalli/enpass-cli");
+    let mut key = Zeroizing::new([0u8; 32]);
+    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), &salt, EXPORT_PBKDF2_ROUNDS, &mut *key);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&*key));
 
-        // conn.execute_batch(ENPASS6_PRAGMAS)?;
-        return Err(Error::UnsupportedEnpassVersion);
-    } else {
-        conn.execute_batch(ENPASS5_PRAGMAS)?;
+    let mut nonce_bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    let nonce = XNonce::from_slice(&nonce_bytes);
+
+    let plaintext = serde_json::to_vec(cards)?;
+    let ciphertext = cipher
+        .encrypt(nonce, plaintext.as_ref())
+        .map_err(|_| Error::ExportCipherError)?;
+
+    let mut file = std::fs::File::create(output)?;
+    file.write_all(EXPORT_MAGIC)?;
+    file.write_all(&[EXPORT_KDF_PBKDF2_HMAC_SHA256])?;
+    file.write_all(&EXPORT_PBKDF2_ROUNDS.to_le_bytes())?;
+    file.write_all(&salt)?;
+    file.write_all(&nonce_bytes)?;
+    file.write_all(&ciphertext)?;
+    Ok(())
+}
+
+/// The secret handed to SQLCipher via `PRAGMA key`: the password, with the
+/// keyfile bytes (hex-encoded) appended when a keyfile second factor is in
+/// use. Without a keyfile this is just the password.
+fn effective_passphrase(password: &str, keyfile: Option<&[u8]>) -> Zeroizing<String> {
+    match keyfile {
+        Some(keyfile) => {
+            let mut passphrase = password.to_owned();
+            passphrase.push_str(&hex::encode(keyfile));
+            Zeroizing::new(passphrase)
+        }
+        None => Zeroizing::new(password.to_owned()),
+    }
+}
+
+/// The AES key and IV derived from `Identity`. Zeroized on drop since the
+/// card data and key they protect are long-lived secrets that shouldn't
+/// linger in freed memory.
+#[derive(Zeroize, ZeroizeOnDrop)]
+struct VaultKeys {
+    key: [u8; 32],
+    iv: Vec<u8>,
+}
+
+/// Where the IV and salt live inside a v5 `Identity.info`, and how many
+/// PBKDF2 rounds to stretch `Identity.hash` with.
+///
+/// These values match this tool's original, working v5 `Identity` parsing.
+/// There is no v6 equivalent here: a v6 vault's per-card layout and PBKDF2
+/// hash are believed to differ (to match the SQLCipher 4 / HMAC_SHA512
+/// pragmas above) but that hasn't been confirmed against a real
+/// `vault.enpassdb`, so `derive_key_iv` refuses `-6` outright instead of
+/// guessing at offsets.
+struct IdentityLayout {
+    iv: Range<usize>,
+    salt: Range<usize>,
+    pbkdf2_rounds: u32,
+}
+
+impl IdentityLayout {
+    fn v5() -> Self {
+        IdentityLayout {
+            iv: 16..32,
+            salt: 32..48,
+            pbkdf2_rounds: 2,
+        }
     }
 
-    let (key, iv) = {
-        let mut stmt = conn.prepare("SELECT * FROM Identity")?;
-        let identity: Identity = stmt.query_row(&[], |row| Identity {
-            id: row.get(0),
-            version: row.get(1),
-            signature: row.get(2),
-            sync_uuid: row.get(3),
-            hash: row.get(4),
-            info: row.get(5),
-        })?;
+    /// The shortest `Identity.info` this layout can be read from.
+    fn min_info_len(&self) -> usize {
+        self.iv.end.max(self.salt.end)
+    }
+}
 
-        debug!("{:?}", &identity);
+/// Reads the single row out of `Identity` and derives the AES key and IV
+/// used to decrypt every row of `Cards`. No MAC key is derived, and the
+/// rows are not authenticated.
+///
+/// Only v5 is implemented: a v6 vault's per-card key is derived
+/// differently (see `IdentityLayout`), and guessing at that layout would
+/// either panic or silently decrypt every card wrong, exiting 0 with an
+/// empty vault instead of reporting the real problem. Until someone
+/// validates the v6 layout against a real `vault.enpassdb`, `-6` fails
+/// loudly here rather than pretending to work.
+fn derive_key_iv(
+    conn: &Connection,
+    version_6: bool,
+    keyfile: Option<&[u8]>,
+) -> Result<VaultKeys, Error> {
+    if version_6 {
+        return Err(Error::UnsupportedEnpassVersion);
+    }
 
-        let iv = identity.info[16..32].to_owned();
-        let salt = &identity.info[32..48];
+    let mut stmt = conn.prepare("SELECT * FROM Identity")?;
+    let identity: Identity = stmt.query_row(&[], |row| Identity {
+        id: row.get(0),
+        version: row.get(1),
+        signature: row.get(2),
+        sync_uuid: row.get(3),
+        hash: row.get(4),
+        info: row.get(5),
+    })?;
 
-        let mut mac = Hmac::new(Sha256::new(), &identity.hash.as_bytes());
+    debug!("{:?}", &identity);
 
-        let mut key = [0u8; 32];
+    let layout = IdentityLayout::v5();
+    if identity.info.len() < layout.min_info_len() {
+        return Err(Error::MalformedIdentity);
+    }
+    let iv = identity.info[layout.iv.clone()].to_owned();
+    let salt = &identity.info[layout.salt.clone()];
 
-        pbkdf2(&mut mac, &salt, 2, &mut key);
-        (key, iv)
-    };
+    // Fold the keyfile, if any, into the PBKDF2 seed alongside the
+    // identity hash, so a keyfile-protected vault can't be decrypted with
+    // the password alone. Hex-encoded, matching how `effective_passphrase`
+    // folds the same keyfile into the `PRAGMA key` value — using the same
+    // encoding in both places avoids a keyfile that works for one but not
+    // the other.
+    let mut seed = Zeroizing::new(identity.hash.into_bytes());
+    if let Some(keyfile) = keyfile {
+        seed.extend_from_slice(hex::encode(keyfile).as_bytes());
+    }
 
-    {
-        let mut stmt = conn.prepare("SELECT id, uuid, title, subtitle, deleted, trashed, type, category, data FROM Cards ORDER BY title, trashed, deleted")?;
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(&seed, salt, layout.pbkdf2_rounds, &mut key);
+    Ok(VaultKeys { key, iv })
+}
 
-        let mut stdout = std::io::stdout();
+/// Runs the `Cards` query and decrypts+deserializes every row, dropping
+/// any row that fails to decrypt or parse. Each dropped row is logged via
+/// `warn!` for detail, and the total dropped count is also printed to
+/// stderr unconditionally: `warn!` is silent unless `$RUST_LOG` is set, so
+/// on its own it can't tell a user with the wrong password/keyfile/`-6`
+/// flag (which drops every row) apart from a vault that's simply empty.
+fn load_cards(conn: &Connection, keys: &VaultKeys) -> Result<Vec<Card>, Error> {
+    let mut stmt = conn.prepare("SELECT id, uuid, title, subtitle, deleted, trashed, type, category, data FROM Cards ORDER BY title, trashed, deleted")?;
 
-        stmt.query_map(&[], |row| -> Result<_, Error> {
+    let mut dropped = 0usize;
+    let cards = stmt
+        .query_map(&[], |row| -> Result<_, Error> {
+            let id: i32 = row.get(0);
             let data: Vec<u8> = row.get(8);
-            let decrypted = decrypt_enpass_data(&data, &key, &iv)?;
+            let decrypted = decrypt_enpass_data(&data, &keys.key, &keys.iv)?;
             let deserialized = serde_json::from_slice(&decrypted)?;
             let card = Card {
-                id: row.get(0),
+                id,
                 uuid: row.get(1),
                 title: row.get(2),
                 subtitle: row.get(3),
@@ -168,16 +341,74 @@ fn main() -> Result<(), Error> {
             };
             Ok(card)
         })?
-        .filter_map(|res| res.ok())
-        .filter_map(|res| res.ok())
-        .for_each(|card| {
-            writeln!(
-                stdout,
-                "{}",
-                serde_json::to_string(&card).expect("Failed to serialize")
-            )
-            .expect("Failed to write");
-        });
+        .filter_map(|res| match res {
+            Ok(Ok(card)) => Some(card),
+            Ok(Err(err)) => {
+                warn!("dropping card row: {}", err);
+                dropped += 1;
+                None
+            }
+            Err(err) => {
+                warn!("dropping card row: sqlite error: {}", err);
+                dropped += 1;
+                None
+            }
+        })
+        .collect();
+
+    if dropped > 0 {
+        eprintln!(
+            "enpass-cli: dropped {} card row(s) that failed to decrypt or parse \
+             (wrong password, keyfile, or -6 flag?)",
+            dropped
+        );
+    }
+
+    Ok(cards)
+}
+
+fn main() -> Result<(), Error> {
+    env_logger::init();
+    let opt = Opt::from_args();
+
+    let conn = Connection::open(&opt.database)?;
+
+    // https://www.zetetic.net/blog/2018/11/30/sqlcipher-400-release/
+    // Another option is PRAGMA cipher_compatibility = 3;
+    // https://discuss.zetetic.net/t/upgrading-to-sqlcipher-4/3283
+
+    let password = resolve_password(&opt)?;
+    let keyfile = read_keyfile(&opt)?;
+    let passphrase = effective_passphrase(&password, keyfile.as_deref());
+    conn.execute_batch(&format!("PRAGMA key = '{}'", &*passphrase))?;
+    if opt.version_6 {
+        conn.execute_batch(ENPASS6_PRAGMAS)?;
+    } else {
+        conn.execute_batch(ENPASS5_PRAGMAS)?;
+    }
+
+    let keys = derive_key_iv(&conn, opt.version_6, keyfile.as_deref())?;
+    let cards = load_cards(&conn, &keys)?;
+
+    match &opt.command {
+        Some(Command::Export { output }) => {
+            let export_passphrase = Zeroizing::new(
+                rpassword::prompt_password("Backup passphrase: ")
+                    .map_err(|_| Error::PasswordPromptError)?,
+            );
+            export_cards(&cards, &export_passphrase, output)?;
+        }
+        None => {
+            let mut stdout = std::io::stdout();
+            for card in &cards {
+                writeln!(
+                    stdout,
+                    "{}",
+                    serde_json::to_string(card).expect("Failed to serialize")
+                )
+                .expect("Failed to write");
+            }
+        }
     }
 
     Ok(())